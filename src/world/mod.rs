@@ -14,26 +14,467 @@
 
 pub mod block;
 
-use std::sync::Arc;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::iter;
+use std::collections::{HashMap, VecDeque};
 use std::hash::{BuildHasherDefault, Hasher};
+use std::path::PathBuf;
+use std::fs;
 use types::bit;
 use types::nibble;
 use protocol;
 
+// Upper bound on the number of chunks kept resident in `World::chunks`
+// at once before the least-recently-touched one is flushed to the
+// `ChunkStore` and dropped from memory.
+const MAX_RESIDENT_CHUNKS: usize = 1024;
+
+// Number of background threads used to decode incoming chunk packets.
+const CHUNK_WORKER_THREADS: usize = 4;
+
+// Decodes and inserts chunk sections on the caller's thread.
+pub trait WorldClient {
+    fn load_chunk(&mut self, x: i32, z: i32, new: bool, mask: u16, data: Vec<u8>) -> Result<(), protocol::Error>;
+}
+
+// Decodes chunk sections on a background worker pool; results are
+// picked up later by `World::process_chunk_loads`.
+pub trait AsyncWorldClient {
+    fn load_chunk_async(&mut self, x: i32, z: i32, new: bool, mask: u16, data: Vec<u8>) -> ChunkLoadHandle;
+}
+
+// A receipt for a chunk submitted via `load_chunk_async`. Doesn't need
+// to be polled; included so callers can identify the submission.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkLoadHandle {
+    pub x: i32,
+    pub z: i32,
+}
+
+struct ChunkLoadTask {
+    x: i32,
+    z: i32,
+    new: bool,
+    mask: u16,
+    data: Vec<u8>,
+    // Per-(x, z) submission order, so a result that finishes decoding
+    // late can be told apart from one that's merely late to arrive.
+    seq: u64,
+}
+
+struct ChunkLoadResult {
+    x: i32,
+    z: i32,
+    new: bool,
+    mask: u16,
+    sections: Vec<(u8, Section)>,
+    seq: u64,
+}
+
+struct ChunkWorkerPool {
+    tasks: mpsc::Sender<ChunkLoadTask>,
+}
+
+impl ChunkWorkerPool {
+    fn new(results: mpsc::Sender<ChunkLoadResult>) -> ChunkWorkerPool {
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0 .. CHUNK_WORKER_THREADS {
+            let rx = rx.clone();
+            let results = results.clone();
+            thread::spawn(move || {
+                loop {
+                    let task: ChunkLoadTask = {
+                        let rx = rx.lock().unwrap();
+                        match rx.recv() {
+                            Ok(task) => task,
+                            Err(_) => return,
+                        }
+                    };
+                    match decode_chunk_sections(task.x, task.z, task.mask, task.data) {
+                        Ok(sections) => {
+                            let _ = results.send(ChunkLoadResult {
+                                x: task.x,
+                                z: task.z,
+                                new: task.new,
+                                mask: task.mask,
+                                sections: sections,
+                                seq: task.seq,
+                            });
+                        },
+                        Err(_) => {}, // Malformed packet, drop it on the floor.
+                    }
+                }
+            });
+        }
+        ChunkWorkerPool {
+            tasks: tx,
+        }
+    }
+
+    fn submit(&self, task: ChunkLoadTask) {
+        let _ = self.tasks.send(task);
+    }
+}
+
+// Decodes section bit-size headers, palette, bit data and both nibble
+// light arrays out of a `load_chunk` packet payload. Safe to run off
+// the main thread since it never touches `World::chunks`.
+fn decode_chunk_sections(x: i32, z: i32, mask: u16, data: Vec<u8>) -> Result<Vec<(u8, Section)>, protocol::Error> {
+    use std::io::{Cursor, Read};
+    use byteorder::ReadBytesExt;
+    use protocol::{VarInt, Serializable, LenPrefixed};
+
+    let mut data = Cursor::new(data);
+    let mut sections = vec![];
+
+    for i in 0 .. 16 {
+        if mask & (1 << i) == 0 {
+            continue;
+        }
+        let mut section = Section::new(x, i as u8, z);
+        section.dirty = true;
+
+        let bit_size = try!(data.read_u8());
+        let mut block_map = HashMap::with_hasher(BuildHasherDefault::<FNVHash>::default());
+        if bit_size <= 8 {
+            let count = try!(VarInt::read_from(&mut data)).0;
+            for i in 0 .. count {
+                let id = try!(VarInt::read_from(&mut data)).0;
+                block_map.insert(i as usize, id);
+            }
+        }
+
+        let bits = try!(LenPrefixed::<VarInt, u64>::read_from(&mut data)).data;
+        let m = bit::Map::from_raw(bits, bit_size as usize);
+
+        for i in 0 .. 4096 {
+            let val = m.get(i);
+            let block_id = block_map.get(&val).map(|v| *v as usize).unwrap_or(val);
+            let block = block::Block::by_vanilla_id(block_id);
+            let i = i as i32;
+            section.set_block(
+                i & 0xF,
+                i >> 8,
+                (i >> 4) & 0xF,
+                block
+            );
+        }
+
+        try!(data.read_exact(&mut section.block_light_mut().data));
+        try!(data.read_exact(&mut section.sky_light_mut().data));
+
+        sections.push((i as u8, section));
+    }
+
+    Ok(sections)
+}
+
+// Applies freshly-decoded sections to a chunk. If a section already
+// exists at that index (this is an update, not a brand-new chunk) only
+// its block/light data is replaced in place, so its `Arc<SectionKey>`
+// identity and `building` flag survive the update.
+fn merge_decoded_sections(chunk: &mut Chunk, sections: Vec<(u8, Section)>) {
+    for (i, decoded) in sections {
+        match chunk.sections[i as usize].as_mut() {
+            Some(existing) => {
+                existing.storage = decoded.storage;
+                existing.block_light = decoded.block_light;
+                existing.sky_light = decoded.sky_light;
+                existing.dirty = true;
+            },
+            None => {
+                chunk.sections[i as usize] = Some(decoded);
+            },
+        }
+    }
+}
+
+// A place to put chunks evicted from `World::chunks` so they can be
+// rehydrated later without asking the server to resend them. `Send +
+// Sync` so it can be shared with the background `EvictionWorker`.
+pub trait ChunkStore: Send + Sync {
+    fn get(&self, pos: CPos) -> Option<Vec<u8>>;
+    fn put(&self, pos: CPos, data: &[u8]);
+}
+
+// The default `ChunkStore`: one small file per chunk under a base
+// directory, named after its `CPos`.
+pub struct FileChunkStore {
+    base: PathBuf,
+}
+
+impl FileChunkStore {
+    pub fn new<P: Into<PathBuf>>(base: P) -> FileChunkStore {
+        let base = base.into();
+        let _ = fs::create_dir_all(&base);
+        FileChunkStore {
+            base: base,
+        }
+    }
+
+    fn path_for(&self, pos: CPos) -> PathBuf {
+        self.base.join(format!("{}.{}.chunk", pos.0, pos.1))
+    }
+}
+
+impl ChunkStore for FileChunkStore {
+    fn get(&self, pos: CPos) -> Option<Vec<u8>> {
+        use std::io::Read;
+        let mut file = match fs::File::open(self.path_for(pos)) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+        let mut data = vec![];
+        match file.read_to_end(&mut data) {
+            Ok(_) => Some(data),
+            Err(_) => None,
+        }
+    }
+
+    fn put(&self, pos: CPos, data: &[u8]) {
+        use std::io::Write;
+        let file = fs::File::create(self.path_for(pos));
+        if let Ok(mut file) = file {
+            let _ = file.write_all(data);
+        }
+    }
+}
+
+// Packs a `Chunk` into a compact record: each of the 16 sections (or a
+// single zero byte for an empty slot) followed by its storage form,
+// both nibble light arrays, then the chunk's biomes.
+fn serialize_chunk(chunk: &Chunk) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+    use protocol::{VarInt, Serializable};
+
+    let mut out = vec![];
+    for section in &chunk.sections {
+        match *section {
+            Some(ref section) => {
+                out.write_u8(1).unwrap();
+                match section.storage {
+                    SectionStorage::Uniform(block) => {
+                        out.write_u8(0).unwrap();
+                        VarInt(block.get_steven_id() as i32).write_to(&mut out).unwrap();
+                    },
+                    SectionStorage::Paletted { ref blocks, ref palette, .. } => {
+                        out.write_u8(1).unwrap();
+                        out.write_u8(blocks.bit_size as u8).unwrap();
+                        VarInt(palette.len() as i32).write_to(&mut out).unwrap();
+                        for entry in palette {
+                            VarInt(entry.block.get_steven_id() as i32).write_to(&mut out).unwrap();
+                            VarInt(entry.count.map_or(0, |c| c.get()) as i32).write_to(&mut out).unwrap();
+                        }
+                        let raw = blocks.raw();
+                        VarInt(raw.len() as i32).write_to(&mut out).unwrap();
+                        for word in raw {
+                            out.write_u64::<::byteorder::BigEndian>(*word).unwrap();
+                        }
+                    },
+                }
+                match section.block_light {
+                    Some(ref light) => out.extend_from_slice(&light.data),
+                    None => out.extend(iter::repeat(0u8).take(SECTION_LIGHT_VALUES / 2)),
+                }
+                match section.sky_light {
+                    Some(ref light) => out.extend_from_slice(&light.data),
+                    None => out.extend(iter::repeat(0xFFu8).take(SECTION_LIGHT_VALUES / 2)),
+                }
+            },
+            None => {
+                out.write_u8(0).unwrap();
+            },
+        }
+    }
+    out.extend_from_slice(&chunk.biomes);
+    out
+}
+
+// Serializes and writes evicted chunks to the `ChunkStore` off the
+// calling thread, so draining the LRU list on a hot path (a tick, or
+// process_chunk_loads) never blocks on disk I/O.
+struct EvictionWorker {
+    tasks: mpsc::Sender<(CPos, Chunk)>,
+}
+
+impl EvictionWorker {
+    fn new(store: Arc<ChunkStore>) -> EvictionWorker {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (pos, chunk) in rx {
+                store.put(pos, &serialize_chunk(&chunk));
+            }
+        });
+        EvictionWorker {
+            tasks: tx,
+        }
+    }
+
+    fn submit(&self, pos: CPos, chunk: Chunk) {
+        let _ = self.tasks.send((pos, chunk));
+    }
+}
+
+// The inverse of `serialize_chunk`.
+fn deserialize_chunk(pos: CPos, data: &[u8]) -> Result<Chunk, protocol::Error> {
+    use std::io::{Cursor, Read};
+    use byteorder::ReadBytesExt;
+    use protocol::{VarInt, Serializable};
+
+    let mut data = Cursor::new(data);
+    let mut chunk = Chunk::new(pos);
+
+    for i in 0 .. 16 {
+        let tag = try!(data.read_u8());
+        if tag == 0 {
+            continue;
+        }
+
+        let storage_tag = try!(data.read_u8());
+        let storage = if storage_tag == 0 {
+            let block = block::Block::by_steven_id(try!(VarInt::read_from(&mut data)).0 as usize);
+            SectionStorage::Uniform(block)
+        } else {
+            let bit_size = try!(data.read_u8());
+            let palette_len = try!(VarInt::read_from(&mut data)).0;
+            let mut palette = Vec::with_capacity(palette_len as usize);
+            let mut rev_palette = HashMap::with_hasher(BuildHasherDefault::<FNVHash>::default());
+            for idx in 0 .. palette_len {
+                let block = block::Block::by_steven_id(try!(VarInt::read_from(&mut data)).0 as usize);
+                let count = try!(VarInt::read_from(&mut data)).0 as u32;
+                if count > 0 {
+                    rev_palette.insert(block, idx as usize);
+                }
+                palette.push(PaletteEntry { block: block, count: ::std::num::NonZeroU32::new(count) });
+            }
+
+            let word_count = try!(VarInt::read_from(&mut data)).0;
+            let mut raw = Vec::with_capacity(word_count as usize);
+            for _ in 0 .. word_count {
+                raw.push(try!(data.read_u64::<::byteorder::BigEndian>()));
+            }
+
+            SectionStorage::Paletted {
+                blocks: bit::Map::from_raw(raw, bit_size as usize),
+                palette: palette,
+                rev_palette: rev_palette,
+            }
+        };
+
+        let mut block_light = nibble::Array::new(16 * 16 * 16);
+        try!(data.read_exact(&mut block_light.data));
+        let mut sky_light = nibble::Array::new(16 * 16 * 16);
+        try!(data.read_exact(&mut sky_light.data));
+
+        chunk.sections[i] = Some(Section {
+            key: Arc::new(SectionKey { pos: (pos.0, i as u8, pos.1) }),
+            y: i as u8,
+
+            storage: storage,
+
+            block_light: Some(block_light),
+            sky_light: Some(sky_light),
+
+            dirty: true,
+            building: false,
+        });
+    }
+
+    try!(data.read_exact(&mut chunk.biomes));
+
+    Ok(chunk)
+}
+
 pub struct World {
     chunks: HashMap<CPos, Chunk>,
+    chunk_worker_pool: ChunkWorkerPool,
+    chunk_results: mpsc::Receiver<ChunkLoadResult>,
+    store: Arc<ChunkStore>,
+    eviction_worker: EvictionWorker,
+    lru: VecDeque<CPos>,
+    // Per-chunk submission/apply counters so a decode result that was
+    // submitted earlier but finishes later can't clobber a newer one.
+    chunk_seq: HashMap<CPos, u64>,
+    chunk_applied_seq: HashMap<CPos, u64>,
 }
 
 impl World {
     pub fn new() -> World {
+        World::new_with_store(Box::new(FileChunkStore::new("chunks")))
+    }
+
+    pub fn new_with_store(store: Box<ChunkStore>) -> World {
+        let store: Arc<ChunkStore> = Arc::from(store);
+        let (tx, rx) = mpsc::channel();
         World {
             chunks: HashMap::new(),
+            chunk_worker_pool: ChunkWorkerPool::new(tx),
+            chunk_results: rx,
+            eviction_worker: EvictionWorker::new(store.clone()),
+            store: store,
+            lru: VecDeque::new(),
+            chunk_seq: HashMap::new(),
+            chunk_applied_seq: HashMap::new(),
         }
     }
 
-    pub fn is_chunk_loaded(&self, x: i32, z: i32) -> bool {
-        self.chunks.contains_key(&CPos(x, z))
+    fn next_chunk_seq(&mut self, cpos: CPos) -> u64 {
+        let counter = self.chunk_seq.entry(cpos).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    // Records `seq` as applied for `cpos` and returns whether it should
+    // actually be merged in, i.e. it isn't older than one already applied.
+    fn try_apply_chunk_seq(&mut self, cpos: CPos, seq: u64) -> bool {
+        if let Some(&applied) = self.chunk_applied_seq.get(&cpos) {
+            if seq < applied {
+                return false;
+            }
+        }
+        self.chunk_applied_seq.insert(cpos, seq);
+        true
+    }
+
+    // Drains chunk sections that have finished decoding on the
+    // background worker pool since the last call and applies them.
+    // Cheap enough to call once per tick.
+    pub fn process_chunk_loads(&mut self) {
+        loop {
+            let result = match self.chunk_results.try_recv() {
+                Ok(result) => result,
+                Err(_) => break,
+            };
+            let cpos = CPos(result.x, result.z);
+            if !self.try_apply_chunk_seq(cpos, result.seq) {
+                continue;
+            }
+            if !self.chunks.contains_key(&cpos) {
+                if result.new {
+                    self.chunks.insert(cpos, Chunk::new(cpos));
+                } else if !self.rehydrate_chunk(cpos) {
+                    continue;
+                }
+            }
+            {
+                let chunk = self.chunks.get_mut(&cpos).unwrap();
+                merge_decoded_sections(chunk, result.sections);
+            }
+            self.touch_lru(cpos);
+            self.flag_neighbors_dirty(result.x, result.mask, result.z);
+        }
+    }
+
+    pub fn is_chunk_loaded(&mut self, x: i32, z: i32) -> bool {
+        let cpos = CPos(x, z);
+        if self.chunks.contains_key(&cpos) {
+            self.touch_lru(cpos);
+            return true;
+        }
+        self.rehydrate_chunk(cpos)
     }
 
     pub fn set_block(&mut self, x: i32, y: i32, z: i32, b: block::Block) {
@@ -43,15 +484,62 @@ impl World {
         }
         let chunk = self.chunks.get_mut(&cpos).unwrap();
         chunk.set_block(x & 0xF, y, z & 0xF, b);
+        self.touch_lru(cpos);
     }
 
-    pub fn get_block(&self, x: i32, y: i32, z: i32) -> block::Block {
-        match self.chunks.get(&CPos(x >> 4, z >> 4)) {
+    pub fn get_block(&mut self, x: i32, y: i32, z: i32) -> block::Block {
+        let cpos = CPos(x >> 4, z >> 4);
+        if self.chunks.contains_key(&cpos) {
+            self.touch_lru(cpos);
+        } else {
+            self.rehydrate_chunk(cpos);
+        }
+        match self.chunks.get(&cpos) {
             Some(ref chunk) => chunk.get_block(x & 0xF, y, z & 0xF),
             None => block::Missing{},
         }
     }
 
+    // Brings a chunk back into `self.chunks` from the `ChunkStore` if
+    // it isn't resident already. Returns whether it ended up loaded.
+    fn rehydrate_chunk(&mut self, pos: CPos) -> bool {
+        if self.chunks.contains_key(&pos) {
+            return true;
+        }
+        let data = match self.store.get(pos) {
+            Some(data) => data,
+            None => return false,
+        };
+        match deserialize_chunk(pos, &data) {
+            Ok(chunk) => {
+                self.chunks.insert(pos, chunk);
+                self.touch_lru(pos);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    // Marks `pos` as most-recently-used and evicts the least-recently-
+    // used chunk(s) to the `ChunkStore` if that's over `MAX_RESIDENT_CHUNKS`.
+    fn touch_lru(&mut self, pos: CPos) {
+        if let Some(idx) = self.lru.iter().position(|p| *p == pos) {
+            self.lru.remove(idx);
+        }
+        self.lru.push_back(pos);
+        while self.lru.len() > MAX_RESIDENT_CHUNKS {
+            let evict = match self.lru.pop_front() {
+                Some(evict) => evict,
+                None => break,
+            };
+            if let Some(chunk) = self.chunks.remove(&evict) {
+                self.eviction_worker.submit(evict, chunk);
+            }
+            self.chunk_seq.remove(&evict);
+            self.chunk_applied_seq.remove(&evict);
+        }
+    }
+
     pub fn get_dirty_chunk_sections(&mut self) -> Vec<(i32, i32, i32, Arc<SectionKey>)> {
         let mut out = vec![];
         for (_, chunk) in &mut self.chunks {
@@ -156,7 +644,14 @@ impl World {
                         }
                     }
                 }
-                // TODO: Biomes
+                for zz in z1 .. z2 {
+                    for xx in x1 .. x2 {
+                        let ox = xx + (cx << 4);
+                        let oz = zz + (cz << 4);
+                        let sidx = ((ox - snapshot.x) + (oz - snapshot.z) * snapshot.w) as usize;
+                        snapshot.biomes[sidx] = chunk.biomes[(xx + zz * 16) as usize];
+                    }
+                }
             }
         }
 
@@ -164,68 +659,18 @@ impl World {
     }
 
     pub fn unload_chunk(&mut self, x: i32, z: i32) {
-        self.chunks.remove(&CPos(x, z));
-    }
-
-    pub fn load_chunk(&mut self, x: i32, z: i32, new: bool, mask: u16, data: Vec<u8>) -> Result<(), protocol::Error> {
-        use std::io::{Cursor, Read};
-        use byteorder::ReadBytesExt;
-        use protocol::{VarInt, Serializable, LenPrefixed};
-
-        let mut data = Cursor::new(data);
-
         let cpos = CPos(x, z);
-        {
-            let chunk = if new {
-                self.chunks.insert(cpos, Chunk::new(cpos));
-                self.chunks.get_mut(&cpos).unwrap()
-            } else {
-                if !self.chunks.contains_key(&cpos) {
-                    return Ok(());
-                }
-                self.chunks.get_mut(&cpos).unwrap()
-            };
-
-            for i in 0 .. 16 {
-                if mask & (1 << i) == 0 {
-                    continue;
-                }
-                if chunk.sections[i].is_none() {
-                    chunk.sections[i] = Some(Section::new(x, i as u8, z));
-                }
-                let section = chunk.sections[i as usize].as_mut().unwrap();
-                section.dirty = true;
-
-                let bit_size = try!(data.read_u8());
-                let mut block_map = HashMap::with_hasher(BuildHasherDefault::<FNVHash>::default());
-                if bit_size <= 8 {
-                    let count = try!(VarInt::read_from(&mut data)).0;
-                    for i in 0 .. count {
-                        let id = try!(VarInt::read_from(&mut data)).0;
-                        block_map.insert(i as usize, id);
-                    }
-                }
-
-                let bits = try!(LenPrefixed::<VarInt, u64>::read_from(&mut data)).data;
-                let m = bit::Map::from_raw(bits, bit_size as usize);
-
-                for i in 0 .. 4096 {
-                    let val = m.get(i);
-                    let block_id = block_map.get(&val).map(|v| *v as usize).unwrap_or(val);
-                    let block = block::Block::by_vanilla_id(block_id);
-                    let i = i as i32;
-                    section.set_block(
-                        i & 0xF,
-                        i >> 8,
-                        (i >> 4) & 0xF,
-                        block
-                    );
-                }
-
-                try!(data.read_exact(&mut section.block_light.data));
-                try!(data.read_exact(&mut section.sky_light.data));
-            }
+        if let Some(chunk) = self.chunks.remove(&cpos) {
+            self.eviction_worker.submit(cpos, chunk);
+        }
+        self.chunk_seq.remove(&cpos);
+        self.chunk_applied_seq.remove(&cpos);
+        if let Some(idx) = self.lru.iter().position(|p| *p == cpos) {
+            self.lru.remove(idx);
         }
+    }
+
+    fn flag_neighbors_dirty(&mut self, x: i32, mask: u16, z: i32) {
         for i in 0 .. 16 {
             if mask & (1 << i) == 0 {
                 continue;
@@ -237,7 +682,6 @@ impl World {
                 self.flag_section_dirty(x + pos.0, i as i32 + pos.1, z + pos.2);
             }
         }
-        Ok(())
     }
 
     fn flag_section_dirty(&mut self, x: i32, y: i32, z: i32) {
@@ -253,6 +697,44 @@ impl World {
     }
 }
 
+impl WorldClient for World {
+    fn load_chunk(&mut self, x: i32, z: i32, new: bool, mask: u16, data: Vec<u8>) -> Result<(), protocol::Error> {
+        let cpos = CPos(x, z);
+        if new {
+            self.chunks.insert(cpos, Chunk::new(cpos));
+        } else if !self.rehydrate_chunk(cpos) {
+            return Ok(());
+        }
+
+        let seq = self.next_chunk_seq(cpos);
+        let sections = try!(decode_chunk_sections(x, z, mask, data));
+        if self.try_apply_chunk_seq(cpos, seq) {
+            let chunk = self.chunks.get_mut(&cpos).unwrap();
+            merge_decoded_sections(chunk, sections);
+        }
+        self.touch_lru(cpos);
+        self.flag_neighbors_dirty(x, mask, z);
+        Ok(())
+    }
+}
+
+impl AsyncWorldClient for World {
+    fn load_chunk_async(&mut self, x: i32, z: i32, new: bool, mask: u16, data: Vec<u8>) -> ChunkLoadHandle {
+        let cpos = CPos(x, z);
+        if new {
+            self.chunks.insert(cpos, Chunk::new(cpos));
+            self.touch_lru(cpos);
+        } else {
+            self.rehydrate_chunk(cpos);
+        }
+        let seq = self.next_chunk_seq(cpos);
+        self.chunk_worker_pool.submit(ChunkLoadTask {
+            x: x, z: z, new: new, mask: mask, data: data, seq: seq,
+        });
+        ChunkLoadHandle { x: x, z: z }
+    }
+}
+
 pub struct Snapshot {
     blocks: Vec<u16>,
     block_light: nibble::Array,
@@ -306,6 +788,118 @@ impl Snapshot {
     fn index(&self, x: i32, y: i32, z: i32) -> usize {
         ((x - self.x) + ((z - self.z) * self.w) + ((y - self.y) * self.w * self.d)) as usize
     }
+
+    // Scans each (x, z) column top-down for the first non-air,
+    // non-missing block, tints it by biome and shades it by height and
+    // light, and encodes the result as a PNG. Columns with nothing but
+    // air/missing blocks are left transparent.
+    pub fn render_top_down_png(&self) -> Vec<u8> {
+        use png::HasParameters;
+
+        let mut pixels = vec![0u8; (self.w * self.d * 4) as usize];
+
+        for zz in 0 .. self.d {
+            for xx in 0 .. self.w {
+                let mut color = (0u8, 0u8, 0u8);
+                let mut light = 0u8;
+                let mut height = 0;
+                let mut found = false;
+
+                for yy in (0 .. self.h).rev() {
+                    let block = self.get_block(self.x + xx, self.y + yy, self.z + zz);
+                    match block {
+                        block::Air{} | block::Missing{} => continue,
+                        _ => {
+                            color = block_color(block);
+                            light = ::std::cmp::max(
+                                self.get_block_light(self.x + xx, self.y + yy, self.z + zz),
+                                self.get_sky_light(self.x + xx, self.y + yy, self.z + zz),
+                            );
+                            height = yy;
+                            found = true;
+                            break;
+                        },
+                    }
+                }
+
+                let idx = ((zz * self.w + xx) * 4) as usize;
+                if found {
+                    let biome = self.biomes[(xx + zz * self.w) as usize];
+                    let color = tint_for_biome(color, biome);
+                    let shade = shade_factor(height, self.h, light);
+                    pixels[idx] = (color.0 as f32 * shade) as u8;
+                    pixels[idx + 1] = (color.1 as f32 * shade) as u8;
+                    pixels[idx + 2] = (color.2 as f32 * shade) as u8;
+                    pixels[idx + 3] = 255;
+                }
+            }
+        }
+
+        let mut out = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut out, self.w as u32, self.d as u32);
+            encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().expect("writing a PNG header into a Vec<u8> cannot fail");
+            writer.write_image_data(&pixels).expect("writing PNG image data into a Vec<u8> cannot fail");
+        }
+        out
+    }
+}
+
+// Representative RGB color for a block. Blocks this doesn't recognise
+// fall back to a color derived from their id.
+fn block_color(block: block::Block) -> (u8, u8, u8) {
+    match block {
+        block::Grass{..} => (95, 159, 53),
+        block::Dirt{..} => (134, 96, 67),
+        block::Stone{..} => (125, 125, 125),
+        block::Sand{..} => (219, 211, 160),
+        block::Water{..} | block::FlowingWater{..} => (63, 90, 191),
+        block::Lava{..} | block::FlowingLava{..} => (210, 95, 20),
+        block::Leaves{..} | block::Leaves2{..} => (60, 110, 48),
+        block::Log{..} | block::Log2{..} => (102, 81, 51),
+        block::Snow{..} | block::SnowLayer{..} => (240, 240, 240),
+        _ => {
+            let id = block.get_steven_id() as u32;
+            (
+                (id.wrapping_mul(2654435761) >> 24) as u8,
+                (id.wrapping_mul(2654435761) >> 16) as u8,
+                (id.wrapping_mul(2654435761) >> 8) as u8,
+            )
+        },
+    }
+}
+
+// Multiplies a base block color by a rough per-biome tint. Chunk packets
+// are decoded without their biome data, so a chunk's biomes stay 0, and
+// the ChunkStore record keeps them as they are; loaded terrain therefore
+// renders with the biome 0 (ocean-ish) tint everywhere.
+fn tint_for_biome(color: (u8, u8, u8), biome: u8) -> (u8, u8, u8) {
+    let (tr, tg, tb): (u32, u32, u32) = match biome {
+        0 => (178, 178, 210), // ocean-ish: cool blue-grey
+        1 => (255, 255, 255), // plains: neutral
+        2 => (240, 220, 150), // desert-ish: warm
+        3 | 4 | 5 | 6 => (190, 225, 170), // forest/hill-ish: green
+        7 => (160, 200, 255), // frozen-ish: pale blue
+        _ => (210, 210, 210),
+    };
+    (
+        ((color.0 as u32 * tr) / 255) as u8,
+        ((color.1 as u32 * tg) / 255) as u8,
+        ((color.2 as u32 * tb) / 255) as u8,
+    )
+}
+
+// Blends a height-based factor (higher columns are brighter) with the
+// column's light level.
+fn shade_factor(height: i32, max_height: i32, light: u8) -> f32 {
+    let height_frac = if max_height > 0 {
+        height as f32 / max_height as f32
+    } else {
+        1.0
+    };
+    let light_frac = light as f32 / 15.0;
+    (0.5 + 0.5 * height_frac) * (0.3 + 0.7 * light_frac)
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
@@ -385,16 +979,41 @@ pub struct SectionKey {
     pos: (i32, u8, i32),
 }
 
+// A palette slot: the block it stands for and how many voxels
+// reference it. `None` is the free-slot marker.
+#[derive(Clone, Copy)]
+struct PaletteEntry {
+    block: block::Block,
+    count: Option<::std::num::NonZeroU32>,
+}
+
+// Brand new, fully-air-filled sections start out `Uniform` and cost
+// nothing beyond the block value. The first distinct block written
+// upgrades the section in place to the paletted form.
+enum SectionStorage {
+    Uniform(block::Block),
+    Paletted {
+        blocks: bit::Map,
+        palette: Vec<PaletteEntry>,
+        rev_palette: HashMap<block::Block, usize, BuildHasherDefault<FNVHash>>,
+    },
+}
+
+// A section's light array reads as the default value (0 for block
+// light, fully lit for sky light) while it's `None`, so a section made
+// by `Section::new` allocates neither 2048-byte nibble::Array until a
+// light value is first set. Sections decoded from a chunk packet or read
+// back from the ChunkStore always hold both arrays, Uniform or not.
+const SECTION_LIGHT_VALUES: usize = 16 * 16 * 16;
+
 struct Section {
     key: Arc<SectionKey>,
     y: u8,
 
-    blocks: bit::Map,
-    block_map: Vec<(block::Block, u32)>,
-    rev_block_map: HashMap<block::Block, usize, BuildHasherDefault<FNVHash>>,
+    storage: SectionStorage,
 
-    block_light: nibble::Array,
-    sky_light: nibble::Array,
+    block_light: Option<nibble::Array>,
+    sky_light: Option<nibble::Array>,
 
     dirty: bool,
     building: bool,
@@ -402,92 +1021,136 @@ struct Section {
 
 impl Section {
     fn new(x: i32, y: u8, z: i32) -> Section {
-        let mut section = Section {
+        Section {
             key: Arc::new(SectionKey{
                 pos: (x, y, z),
             }),
             y: y,
 
-            blocks: bit::Map::new(4096, 4),
-            block_map: vec![
-                (block::Air{}, 0xFFFFFFFF)
-            ],
-            rev_block_map: HashMap::with_hasher(BuildHasherDefault::default()),
+            storage: SectionStorage::Uniform(block::Air{}),
 
-            block_light: nibble::Array::new(16 * 16 * 16),
-            sky_light: nibble::Array::new(16 * 16 * 16),
+            block_light: None,
+            sky_light: None,
 
             dirty: false,
             building: false,
-        };
-        for i in 0 .. 16*16*16 {
-            section.sky_light.set(i, 0xF);
         }
-        section.rev_block_map.insert(block::Air{}, 0);
-        section
+    }
+
+    fn block_light_mut(&mut self) -> &mut nibble::Array {
+        if self.block_light.is_none() {
+            self.block_light = Some(nibble::Array::new(SECTION_LIGHT_VALUES));
+        }
+        self.block_light.as_mut().unwrap()
+    }
+
+    fn sky_light_mut(&mut self) -> &mut nibble::Array {
+        if self.sky_light.is_none() {
+            let mut light = nibble::Array::new(SECTION_LIGHT_VALUES);
+            for i in 0 .. SECTION_LIGHT_VALUES {
+                light.set(i, 0xF);
+            }
+            self.sky_light = Some(light);
+        }
+        self.sky_light.as_mut().unwrap()
+    }
+
+    // Moves a `Uniform` section into the paletted form, seeding the
+    // palette with the block it used to be uniformly filled with.
+    fn upgrade_to_paletted(&mut self, current: block::Block) {
+        let mut rev_palette = HashMap::with_hasher(BuildHasherDefault::<FNVHash>::default());
+        rev_palette.insert(current, 0);
+        self.storage = SectionStorage::Paletted {
+            blocks: bit::Map::new(4096, 4),
+            palette: vec![PaletteEntry { block: current, count: ::std::num::NonZeroU32::new(4096) }],
+            rev_palette: rev_palette,
+        };
     }
 
     fn get_block(&self, x: i32, y: i32, z: i32) -> block::Block {
-        let idx = self.blocks.get(((y << 8) | (z << 4) | x) as usize);
-        self.block_map[idx].0
+        match self.storage {
+            SectionStorage::Uniform(block) => block,
+            SectionStorage::Paletted { ref blocks, ref palette, .. } => {
+                let idx = blocks.get(((y << 8) | (z << 4) | x) as usize);
+                palette[idx].block
+            },
+        }
     }
 
     fn set_block(&mut self, x: i32, y: i32, z: i32, b: block::Block) {
-        let old = self.get_block(x, y, z);
-        if old == b {
-            return;
-        }
-        // Clean up old block
-        {
-            let idx = self.rev_block_map[&old];
-            let info = &mut self.block_map[idx];
-            info.1 -= 1;
-            if info.1 == 0 { // None left of this type
-                self.rev_block_map.remove(&old);
+        if let SectionStorage::Uniform(current) = self.storage {
+            if current == b {
+                return;
             }
+            self.upgrade_to_paletted(current);
         }
 
-        if !self.rev_block_map.contains_key(&b) {
-            let mut found = false;
-            for (i, ref mut info) in self.block_map.iter_mut().enumerate() {
-                if info.1 == 0 {
-                    info.0 = b;
-                    self.rev_block_map.insert(b, i);
-                    found = true;
-                    break;
+        let pos_idx = ((y << 8) | (z << 4) | x) as usize;
+        if let SectionStorage::Paletted { ref mut blocks, ref mut palette, ref mut rev_palette } = self.storage {
+            // Clean up the old block.
+            {
+                let old_idx = blocks.get(pos_idx);
+                let old = &mut palette[old_idx];
+                old.count = ::std::num::NonZeroU32::new(old.count.map_or(0, |c| c.get()) - 1);
+                if old.count.is_none() { // None left of this type
+                    rev_palette.remove(&old.block);
                 }
             }
-            if !found {
-                if self.block_map.len() >= 1 << self.blocks.bit_size {
-                    let new_size = self.blocks.bit_size << 1;
-                    let new_blocks = self.blocks.resize(new_size);
-                    self.blocks = new_blocks;
+
+            if !rev_palette.contains_key(&b) {
+                let mut found = None;
+                for (i, entry) in palette.iter().enumerate() {
+                    if entry.count.is_none() {
+                        found = Some(i);
+                        break;
+                    }
                 }
-                self.rev_block_map.insert(b, self.block_map.len());
-                self.block_map.push((b, 0));
+                let idx = match found {
+                    Some(i) => {
+                        palette[i].block = b;
+                        i
+                    },
+                    None => {
+                        if palette.len() >= 1 << blocks.bit_size {
+                            let new_size = blocks.bit_size << 1;
+                            let new_blocks = blocks.resize(new_size);
+                            *blocks = new_blocks;
+                        }
+                        palette.push(PaletteEntry { block: b, count: None });
+                        palette.len() - 1
+                    },
+                };
+                rev_palette.insert(b, idx);
             }
-        }
 
-        let idx = self.rev_block_map[&b];
-        let info = &mut self.block_map[idx];
-        info.1 += 1;
-        self.blocks.set(((y << 8) | (z << 4) | x) as usize, idx);
+            let idx = rev_palette[&b];
+            let entry = &mut palette[idx];
+            entry.count = Some(match entry.count {
+                Some(c) => ::std::num::NonZeroU32::new(c.get() + 1).unwrap(),
+                None => ::std::num::NonZeroU32::new(1).unwrap(),
+            });
+            blocks.set(pos_idx, idx);
+        }
         self.dirty = true;
     }
 
     fn get_block_light(&self, x: i32, y: i32, z: i32) -> u8 {
-        self.block_light.get(((y << 8) | (z << 4) | x) as usize)
+        let idx = ((y << 8) | (z << 4) | x) as usize;
+        self.block_light.as_ref().map_or(0, |light| light.get(idx))
     }
 
     fn set_block_light(&mut self, x: i32, y: i32, z: i32, l: u8) {
-        self.block_light.set(((y << 8) | (z << 4) | x) as usize, l);
+        let idx = ((y << 8) | (z << 4) | x) as usize;
+        self.block_light_mut().set(idx, l);
     }
 
     fn get_sky_light(&self, x: i32, y: i32, z: i32) -> u8 {
-        self.sky_light.get(((y << 8) | (z << 4) | x) as usize)
+        let idx = ((y << 8) | (z << 4) | x) as usize;
+        self.sky_light.as_ref().map_or(0xF, |light| light.get(idx))
     }
 
     fn set_sky_light(&mut self, x: i32, y: i32, z: i32, l: u8) {
-        self.sky_light.set(((y << 8) | (z << 4) | x) as usize, l);
+        let idx = ((y << 8) | (z << 4) | x) as usize;
+        self.sky_light_mut().set(idx, l);
     }
 }
\ No newline at end of file